@@ -139,6 +139,810 @@ where
     }
 }
 
+/// A radix-16 trie ("nodemap") that can back an [`IdIndex`] as an
+/// alternative to materializing every id into a sorted `Vec`.
+///
+/// The trie is stored as an array of [`NodeMapBlock`]s, one 16-way branch
+/// per block, indexed by hex nybble. Each slot in a block is either
+/// empty, a leaf pointing at an entry, or a pointer to a child block. The
+/// root is always the last block in `blocks`, which lets the structure be
+/// grown by appending new blocks (see the `insert` support added on top of
+/// this type).
+///
+/// [`NodeMapBlock`] is a fixed 68-byte record ([`NodeMapBlock::to_bytes`]/
+/// [`NodeMapBlock::from_bytes`]), so the same format can live in a file
+/// instead of a `Vec`. A reader doesn't need to load that file into
+/// memory to query it either -- [`resolve_prefix_from_reader`] walks a
+/// prefix's nybbles by seeking to one fixed-size block at a time, the
+/// on-disk analogue of [`NodeMapIndex::resolve_prefix_with`].
+mod nodemap {
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    use super::*;
+
+    /// Number of slots (hex nybbles) in a single block.
+    const BLOCK_SIZE: usize = 16;
+
+    /// On-disk size of a [`NodeMapBlock`]: 16 big-endian i32 nybble slots
+    /// plus one more for its terminal entry.
+    pub(super) const BLOCK_BYTE_LEN: usize = (BLOCK_SIZE + 1) * 4;
+
+    /// A decoded slot in a [`NodeMapBlock`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum NodeMapEntry {
+        Empty,
+        /// Index into the index's entry table.
+        Leaf(usize),
+        /// Index into the index's block table.
+        Child(usize),
+    }
+
+    impl NodeMapEntry {
+        fn decode(raw: i32) -> Self {
+            match raw {
+                0 => NodeMapEntry::Empty,
+                n if n < 0 => NodeMapEntry::Leaf((-n - 1) as usize),
+                n => NodeMapEntry::Child((n - 1) as usize),
+            }
+        }
+
+        fn encode(self) -> i32 {
+            match self {
+                NodeMapEntry::Empty => 0,
+                NodeMapEntry::Leaf(pos) => -((pos as i32) + 1),
+                NodeMapEntry::Child(pos) => (pos as i32) + 1,
+            }
+        }
+    }
+
+    /// One 16-way branch of the trie, stored as 16 big-endian `i32`s so the
+    /// whole structure can be read back from disk record-by-record, plus
+    /// one more slot for a key that ran out of nybbles exactly at this
+    /// block's depth -- an exact byte-prefix of every other key still
+    /// routed through one of the 16 proper slots, which therefore has no
+    /// nybble of its own to pick one of them.
+    #[derive(Debug, Clone, Copy)]
+    pub(super) struct NodeMapBlock {
+        slots: [i32; BLOCK_SIZE],
+        terminal: i32,
+    }
+
+    impl NodeMapBlock {
+        fn empty() -> Self {
+            NodeMapBlock {
+                slots: [0; BLOCK_SIZE],
+                terminal: 0,
+            }
+        }
+
+        fn get(&self, nybble: u8) -> NodeMapEntry {
+            NodeMapEntry::decode(self.slots[nybble as usize])
+        }
+
+        fn set(&mut self, nybble: u8, entry: NodeMapEntry) {
+            self.slots[nybble as usize] = entry.encode();
+        }
+
+        /// The leaf for a key that is an exact byte-prefix of every other
+        /// key routed through this block, if any; always `Leaf` or `Empty`,
+        /// never `Child`.
+        fn terminal(&self) -> NodeMapEntry {
+            NodeMapEntry::decode(self.terminal)
+        }
+
+        fn set_terminal(&mut self, entry: NodeMapEntry) {
+            self.terminal = entry.encode();
+        }
+
+        /// Returns the single occupied entry among this block's 16 nybble
+        /// slots and its terminal leaf, if there is exactly one; `None` if
+        /// two or more of them are occupied (a real branch) or none are.
+        fn only_occupied_slot(&self) -> Option<NodeMapEntry> {
+            let mut occupied = (0..BLOCK_SIZE as u8)
+                .map(|n| self.get(n))
+                .chain(std::iter::once(self.terminal()))
+                .filter(|entry| *entry != NodeMapEntry::Empty);
+            let entry = occupied.next()?;
+            if occupied.next().is_some() {
+                None
+            } else {
+                Some(entry)
+            }
+        }
+
+        /// Encodes this block as the fixed-size record read back by
+        /// [`read_block`].
+        pub(super) fn to_bytes(self) -> [u8; BLOCK_BYTE_LEN] {
+            let mut bytes = [0; BLOCK_BYTE_LEN];
+            for (i, slot) in self.slots.iter().enumerate() {
+                bytes[i * 4..(i + 1) * 4].copy_from_slice(&slot.to_be_bytes());
+            }
+            let terminal_offset = BLOCK_SIZE * 4;
+            bytes[terminal_offset..terminal_offset + 4]
+                .copy_from_slice(&self.terminal.to_be_bytes());
+            bytes
+        }
+
+        /// Decodes a block previously encoded with [`NodeMapBlock::to_bytes`].
+        pub(super) fn from_bytes(bytes: &[u8; BLOCK_BYTE_LEN]) -> Self {
+            let mut block = Self::empty();
+            for (i, slot) in block.slots.iter_mut().enumerate() {
+                *slot = i32::from_be_bytes(bytes[i * 4..(i + 1) * 4].try_into().unwrap());
+            }
+            let terminal_offset = BLOCK_SIZE * 4;
+            block.terminal =
+                i32::from_be_bytes(bytes[terminal_offset..terminal_offset + 4].try_into().unwrap());
+            block
+        }
+    }
+
+    /// An entry stored behind a leaf. Entries with the same key are chained
+    /// together through `next`, oldest first, so a single leaf slot can
+    /// represent more than one value (e.g. divergent changes sharing a
+    /// change id).
+    #[derive(Debug, Clone)]
+    struct Entry<K, V> {
+        key: K,
+        value: V,
+        next: Option<usize>,
+    }
+
+    /// Returns the nybble at hex-digit index `i` of `bytes` (0 is the most
+    /// significant nybble of the first byte).
+    fn get_nybble(bytes: &[u8], i: usize) -> u8 {
+        let byte = bytes[i / 2];
+        if i % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xf
+        }
+    }
+
+    /// Returns the index of the first hex nybble at which `a` and `b`
+    /// differ, or `None` if one is a prefix of the other.
+    pub(super) fn first_different_nybble(a: &[u8], b: &[u8]) -> Option<usize> {
+        for i in 0..a.len().min(b.len()) {
+            if a[i] != b[i] {
+                return Some(if a[i] & 0xf0 != b[i] & 0xf0 {
+                    2 * i
+                } else {
+                    2 * i + 1
+                });
+            }
+        }
+        None
+    }
+
+    /// Reads the block at `block_idx` directly from `reader`, at its fixed
+    /// byte offset, without touching any other block -- the building
+    /// block [`resolve_prefix_from_reader`] uses to query a nodemap file
+    /// one block at a time instead of loading it into a `Vec<NodeMapBlock>`.
+    fn read_block<R: Read + Seek>(reader: &mut R, block_idx: usize) -> io::Result<NodeMapBlock> {
+        reader.seek(SeekFrom::Start((block_idx as u64) * (BLOCK_BYTE_LEN as u64)))?;
+        let mut bytes = [0; BLOCK_BYTE_LEN];
+        reader.read_exact(&mut bytes)?;
+        Ok(NodeMapBlock::from_bytes(&bytes))
+    }
+
+    /// The reader-based counterpart of [`NodeMapIndex::single_descendant_leaf`].
+    fn single_descendant_leaf_from_reader<R: Read + Seek>(
+        reader: &mut R,
+        block: NodeMapBlock,
+    ) -> io::Result<Option<usize>> {
+        match block.only_occupied_slot() {
+            None => Ok(None),
+            Some(NodeMapEntry::Leaf(pos)) => Ok(Some(pos)),
+            Some(NodeMapEntry::Child(child_idx)) => {
+                let child = read_block(reader, child_idx)?;
+                single_descendant_leaf_from_reader(reader, child)
+            }
+            Some(NodeMapEntry::Empty) => {
+                unreachable!("only_occupied_slot returns an occupied slot")
+            }
+        }
+    }
+
+    /// Resolves `prefix` by reading blocks one at a time from `reader`
+    /// instead of requiring them to already be loaded into memory, the
+    /// on-disk analogue of [`NodeMapIndex::resolve_prefix_with`].
+    ///
+    /// On a match, returns the position of the leaf's first entry in the
+    /// index's (in-memory) entry table; looking up its key/value, and any
+    /// further values chained onto it, is left to the caller, since those
+    /// don't live in the block file at all. A caller that wants `NoMatch`
+    /// instead of a false `SingleMatch` for a prefix that merely shares a
+    /// route with some other key should confirm `prefix.matches(..)` on
+    /// the resulting entry's key, the same check
+    /// [`NodeMapIndex::resolve_prefix_with`] makes internally.
+    pub(crate) fn resolve_prefix_from_reader<R: Read + Seek>(
+        reader: &mut R,
+        root: usize,
+        prefix: &HexPrefix,
+    ) -> io::Result<PrefixResolution<usize>> {
+        let prefix_bytes = prefix.min_prefix_bytes();
+        let prefix_len = prefix.hex_len();
+        let mut block_idx = root;
+        let mut depth = 0;
+        loop {
+            let block = read_block(reader, block_idx)?;
+            if depth == prefix_len {
+                return Ok(match single_descendant_leaf_from_reader(reader, block)? {
+                    Some(pos) => PrefixResolution::SingleMatch(pos),
+                    None => PrefixResolution::AmbiguousMatch,
+                });
+            }
+            match block.get(get_nybble(prefix_bytes, depth)) {
+                NodeMapEntry::Empty => return Ok(PrefixResolution::NoMatch),
+                NodeMapEntry::Leaf(pos) => return Ok(PrefixResolution::SingleMatch(pos)),
+                NodeMapEntry::Child(child_idx) => {
+                    block_idx = child_idx;
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct NodeMapIndex<K, V> {
+        entries: Vec<Entry<K, V>>,
+        blocks: Vec<NodeMapBlock>,
+        root: Option<usize>,
+    }
+
+    impl<K, V> NodeMapIndex<K, V>
+    where
+        K: ObjectId + Ord,
+    {
+        /// Builds a trie from the given entries, which need not be sorted.
+        /// Multiple values can be associated with a single key.
+        pub fn from_vec(mut vec: Vec<(K, V)>) -> Self {
+            vec.sort_unstable_by(|(k0, _), (k1, _)| k0.cmp(k1));
+            let mut entries = Vec::with_capacity(vec.len());
+            let mut blocks = Vec::new();
+            let root = if vec.is_empty() {
+                None
+            } else {
+                Some(Self::build_block(vec, 0, &mut entries, &mut blocks))
+            };
+            NodeMapIndex {
+                entries,
+                blocks,
+                root,
+            }
+        }
+
+        /// Index of the root block, for a caller that wants to resolve
+        /// against a serialized copy of this trie with
+        /// [`resolve_prefix_from_reader`].
+        pub fn root(&self) -> Option<usize> {
+            self.root
+        }
+
+        /// Writes every block to `writer`, encoding each with
+        /// [`NodeMapBlock::to_bytes`], so it can later be queried a block
+        /// at a time with [`resolve_prefix_from_reader`] instead of being
+        /// loaded back into a `Vec<NodeMapBlock>`.
+        pub fn write_blocks<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            for block in &self.blocks {
+                writer.write_all(&block.to_bytes())?;
+            }
+            Ok(())
+        }
+
+        /// Writes only the blocks appended since `docket` was produced,
+        /// seeking to its `valid_len` first, and returns a [`NodeMapDocket`]
+        /// covering everything written so far.
+        ///
+        /// Unlike [`Self::write_blocks`], this is safe to call again after
+        /// [`Self::insert`] has appended more blocks: `insert` never
+        /// rewrites a block that already exists, so the bytes below
+        /// `docket.valid_len` are untouched and don't need to be written
+        /// again.
+        pub fn write_new_blocks<W: Write + Seek>(
+            &self,
+            docket: NodeMapDocket,
+            writer: &mut W,
+        ) -> io::Result<NodeMapDocket> {
+            writer.seek(SeekFrom::Start(docket.valid_len))?;
+            for block in &self.blocks[(docket.valid_len as usize / BLOCK_BYTE_LEN)..] {
+                writer.write_all(&block.to_bytes())?;
+            }
+            Ok(NodeMapDocket::new(self))
+        }
+
+        /// Partitions `group` (sorted by key) by the nybble at `depth`,
+        /// recursively building a child block or leaf chain for each
+        /// partition, and appends the resulting block. Returns its index.
+        fn build_block(
+            group: Vec<(K, V)>,
+            depth: usize,
+            entries: &mut Vec<Entry<K, V>>,
+            blocks: &mut Vec<NodeMapBlock>,
+        ) -> usize {
+            let mut block = NodeMapBlock::empty();
+            let mut iter = group.into_iter().peekable();
+
+            // A key with no nybble left at `depth` is an exact byte-prefix
+            // of every other key still in `group` -- sorting puts it
+            // first -- so it can't be routed into one of the 16 nybble
+            // slots below like the rest; it becomes this block's terminal
+            // leaf instead. Any other entries in `group` with that same
+            // key (duplicate values) are necessarily adjacent to it too,
+            // and chain onto the same leaf.
+            if let Some((k, _)) = iter.peek() {
+                if depth >= k.as_bytes().len() * 2 {
+                    let start = entries.len();
+                    let mut bucket = vec![iter.next().unwrap()];
+                    while let Some((k, _)) = iter.peek() {
+                        if *k != bucket[0].0 {
+                            break;
+                        }
+                        bucket.push(iter.next().unwrap());
+                    }
+                    let last = bucket.len() - 1;
+                    for (i, (k, v)) in bucket.into_iter().enumerate() {
+                        entries.push(Entry {
+                            key: k,
+                            value: v,
+                            next: (i != last).then(|| start + i + 1),
+                        });
+                    }
+                    block.set_terminal(NodeMapEntry::Leaf(start));
+                }
+            }
+
+            while let Some((first_key, first_value)) = iter.next() {
+                let nybble = get_nybble(first_key.as_bytes(), depth);
+                let mut same_key = true;
+                let mut bucket = vec![(first_key, first_value)];
+                while let Some((k, _)) = iter.peek() {
+                    if get_nybble(k.as_bytes(), depth) != nybble {
+                        break;
+                    }
+                    if *k != bucket[0].0 {
+                        same_key = false;
+                    }
+                    bucket.push(iter.next().unwrap());
+                }
+                let entry = if bucket.len() == 1 || same_key {
+                    let start = entries.len();
+                    let last = bucket.len() - 1;
+                    for (i, (k, v)) in bucket.into_iter().enumerate() {
+                        entries.push(Entry {
+                            key: k,
+                            value: v,
+                            next: (i != last).then(|| start + i + 1),
+                        });
+                    }
+                    NodeMapEntry::Leaf(start)
+                } else {
+                    NodeMapEntry::Child(Self::build_block(bucket, depth + 1, entries, blocks))
+                };
+                block.set(nybble, entry);
+            }
+            blocks.push(block);
+            blocks.len() - 1
+        }
+
+        /// Collects the values chained from the leaf starting at `pos`.
+        fn collect_chain<U>(&self, pos: usize, mut value_mapper: impl FnMut(&V) -> U) -> Vec<U> {
+            let mut result = Vec::new();
+            let mut cur = Some(pos);
+            while let Some(i) = cur {
+                let entry = &self.entries[i];
+                result.push(value_mapper(&entry.value));
+                cur = entry.next;
+            }
+            result
+        }
+
+        /// Follows the single chain of one-descendant blocks starting at
+        /// `block_idx` down to its sole leaf, or returns `None` if any
+        /// block along the way branches into more than one descendant.
+        fn single_descendant_leaf(&self, block_idx: usize) -> Option<usize> {
+            match self.blocks[block_idx].only_occupied_slot() {
+                None => None,
+                Some(NodeMapEntry::Leaf(pos)) => Some(pos),
+                Some(NodeMapEntry::Child(child_idx)) => self.single_descendant_leaf(child_idx),
+                Some(NodeMapEntry::Empty) => {
+                    unreachable!("only_occupied_slot returns an occupied slot")
+                }
+            }
+        }
+
+        /// Looks up entries with the given prefix, and collects values if
+        /// matched entries have unambiguous keys.
+        pub fn resolve_prefix_with<U>(
+            &self,
+            prefix: &HexPrefix,
+            mut value_mapper: impl FnMut(&V) -> U,
+        ) -> PrefixResolution<Vec<U>> {
+            let Some(mut block_idx) = self.root else {
+                return PrefixResolution::NoMatch;
+            };
+            let prefix_bytes = prefix.min_prefix_bytes();
+            let prefix_len = prefix.hex_len();
+            let mut depth = 0;
+            loop {
+                if depth == prefix_len {
+                    return match self.single_descendant_leaf(block_idx) {
+                        Some(pos) if prefix.matches(&self.entries[pos].key) => {
+                            PrefixResolution::SingleMatch(self.collect_chain(pos, value_mapper))
+                        }
+                        Some(_) => PrefixResolution::NoMatch,
+                        None => PrefixResolution::AmbiguousMatch,
+                    };
+                }
+                let nybble = get_nybble(prefix_bytes, depth);
+                match self.blocks[block_idx].get(nybble) {
+                    NodeMapEntry::Empty => return PrefixResolution::NoMatch,
+                    NodeMapEntry::Leaf(pos) => {
+                        return if prefix.matches(&self.entries[pos].key) {
+                            PrefixResolution::SingleMatch(self.collect_chain(pos, value_mapper))
+                        } else {
+                            PrefixResolution::NoMatch
+                        };
+                    }
+                    NodeMapEntry::Child(child_idx) => {
+                        block_idx = child_idx;
+                        depth += 1;
+                    }
+                }
+            }
+        }
+
+        /// Looks up entries with the given prefix, and collects values if
+        /// matched entries have unambiguous keys.
+        pub fn resolve_prefix(&self, prefix: &HexPrefix) -> PrefixResolution<Vec<V>>
+        where
+            V: Clone,
+        {
+            self.resolve_prefix_with(prefix, |v: &V| v.clone())
+        }
+
+        pub fn has_key(&self, key: &K) -> bool {
+            let Some(mut block_idx) = self.root else {
+                return false;
+            };
+            let key_bytes = key.as_bytes();
+            for depth in 0..key_bytes.len() * 2 {
+                match self.blocks[block_idx].get(get_nybble(key_bytes, depth)) {
+                    NodeMapEntry::Empty => return false,
+                    NodeMapEntry::Leaf(pos) => return self.chain_has_key(pos, key),
+                    NodeMapEntry::Child(child_idx) => block_idx = child_idx,
+                }
+            }
+            // `key` ran out of nybbles before resolving to a nybble slot:
+            // it can only be present as this block's terminal leaf, the
+            // slot reserved for a key that's an exact byte-prefix of every
+            // other key still routed through the block.
+            match self.blocks[block_idx].terminal() {
+                NodeMapEntry::Leaf(pos) => self.chain_has_key(pos, key),
+                NodeMapEntry::Empty => false,
+                NodeMapEntry::Child(_) => unreachable!("terminal slot is never a Child"),
+            }
+        }
+
+        /// Scans the chain of same-key entries starting at `pos` for `key`.
+        fn chain_has_key(&self, pos: usize, key: &K) -> bool {
+            let mut cur = Some(pos);
+            while let Some(i) = cur {
+                if self.entries[i].key == *key {
+                    return true;
+                }
+                cur = self.entries[i].next;
+            }
+            false
+        }
+
+        /// Computes the disambiguation length for a `Leaf` hit at `pos`,
+        /// reached after consuming `depth` nybbles of `prefix` from
+        /// `block_idx`, while walking [`Self::shortest_unique_prefix_len`].
+        ///
+        /// If `block_idx` has a sibling occupied slot (a real branch),
+        /// `prefix`'s nybble at `depth` is what tells the leaf apart from
+        /// whatever lives in that other slot, so `depth + 1` digits are
+        /// needed — this is the common case.
+        ///
+        /// Otherwise `block_idx`'s only occupied slot is this leaf's,
+        /// which (since a fresh `Child` is only ever created for a bucket
+        /// that still has more than one distinct key left to separate)
+        /// means no branch was ever taken on the way here: `pos` is the
+        /// sole distinct key stored in the whole trie. If `prefix` is
+        /// itself (a prefix of) that key, nothing else competes with it,
+        /// so zero digits already suffice; otherwise the digits needed are
+        /// however many `prefix` and the leaf's key have in common, plus
+        /// one.
+        fn leaf_disambiguation_len(
+            &self,
+            block_idx: usize,
+            pos: usize,
+            depth: usize,
+            prefix: &HexPrefix,
+        ) -> usize {
+            if self.blocks[block_idx].only_occupied_slot().is_none() {
+                return depth + 1;
+            }
+            let leaf_key = &self.entries[pos].key;
+            if prefix.matches(leaf_key) {
+                return depth;
+            }
+            let prefix_bytes = prefix.min_prefix_bytes();
+            let prefix_len = prefix.hex_len();
+            let leaf_bytes = leaf_key.as_bytes();
+            let leaf_hex_len = leaf_bytes.len() * 2;
+            let mut diverge = depth + 1;
+            while diverge < prefix_len
+                && diverge < leaf_hex_len
+                && get_nybble(prefix_bytes, diverge) == get_nybble(leaf_bytes, diverge)
+            {
+                diverge += 1;
+            }
+            diverge + 1
+        }
+
+        /// Returns the number of hex digits needed to tell the key(s)
+        /// `prefix` leads to apart from every other key in the trie,
+        /// computed directly from the trie instead of by comparing against
+        /// in-memory neighbors. For an ambiguous `prefix` this is more than
+        /// `prefix`'s own length (see below).
+        ///
+        /// The walk follows `prefix`'s own nybbles from the root. Reaching
+        /// an empty slot before exhausting `prefix` means this is exactly
+        /// the depth at which `prefix` parted ways with every other key
+        /// sharing its path, so the answer is that depth plus one — see
+        /// [`first_different_nybble`] for the analogous computation the
+        /// insert path uses when it has two full keys to compare instead
+        /// of one key and a trie position. Reaching a leaf is handled by
+        /// [`Self::leaf_disambiguation_len`], since a leaf block with only
+        /// one occupied slot needs special care (it can mean the leaf is
+        /// the only distinct key in the whole trie, rather than a branch
+        /// `prefix` diverged from).
+        ///
+        /// If `prefix` runs out of nybbles first, the walk keeps following
+        /// the single remaining branch (ignoring `prefix`, which has
+        /// nothing left to say) until it either finds a block that still
+        /// branches into more than one descendant — meaning `prefix` was
+        /// ambiguous and every nybble down to that point is needed — or it
+        /// finds that `prefix` is an exact prefix of exactly one stored
+        /// leaf, whose own divergence from every other key (found earlier,
+        /// on the way down) already determined the answer.
+        pub fn shortest_unique_prefix_len(&self, prefix: &HexPrefix) -> usize {
+            let Some(mut block_idx) = self.root else {
+                return 0;
+            };
+            let prefix_bytes = prefix.min_prefix_bytes();
+            let prefix_len = prefix.hex_len();
+            let mut depth = 0;
+            while depth < prefix_len {
+                match self.blocks[block_idx].get(get_nybble(prefix_bytes, depth)) {
+                    NodeMapEntry::Empty => return depth + 1,
+                    NodeMapEntry::Leaf(pos) => {
+                        return self.leaf_disambiguation_len(block_idx, pos, depth, prefix);
+                    }
+                    NodeMapEntry::Child(child_idx) => {
+                        block_idx = child_idx;
+                        depth += 1;
+                    }
+                }
+            }
+            // `prefix` is exhausted but we're still inside a block. Follow
+            // the rest of the single remaining branch, if there is one,
+            // down to the point where it either branches for real or ends
+            // in a leaf. If it's already a leaf with no further hops, the
+            // digits of `prefix` we already consumed (if any — `prefix`
+            // might be empty) were enough on their own, since nothing else
+            // lives anywhere under this block.
+            loop {
+                match self.blocks[block_idx].only_occupied_slot() {
+                    None => return depth + 1,
+                    Some(NodeMapEntry::Child(child_idx)) => {
+                        block_idx = child_idx;
+                        depth += 1;
+                    }
+                    Some(NodeMapEntry::Leaf(_)) => return depth,
+                    Some(NodeMapEntry::Empty) => {
+                        unreachable!("only_occupied_slot returns an occupied slot")
+                    }
+                }
+            }
+        }
+
+        /// Inserts `key` → `value`, growing the trie in place.
+        ///
+        /// Only new or modified blocks are appended to `blocks`; every block
+        /// that existed before the call keeps its old contents and index,
+        /// so a reader that already holds the previous [`NodeMapDocket`]
+        /// (and therefore the previous root) can keep using it unaffected
+        /// by this insert.
+        pub fn insert(&mut self, key: K, value: V) {
+            self.root = Some(match self.root {
+                None => {
+                    let pos = self.push_entry(key, value, None);
+                    let mut block = NodeMapBlock::empty();
+                    let nybble = get_nybble(self.entries[pos].key.as_bytes(), 0);
+                    block.set(nybble, NodeMapEntry::Leaf(pos));
+                    self.blocks.push(block);
+                    self.blocks.len() - 1
+                }
+                Some(root) => self.insert_at(root, 0, key, value),
+            });
+        }
+
+        fn push_entry(&mut self, key: K, value: V, next: Option<usize>) -> usize {
+            self.entries.push(Entry { key, value, next });
+            self.entries.len() - 1
+        }
+
+        fn append_to_chain(&mut self, head: usize, new_pos: usize) {
+            let mut i = head;
+            while let Some(next) = self.entries[i].next {
+                i = next;
+            }
+            self.entries[i].next = Some(new_pos);
+        }
+
+        /// Copies the block at `block_idx` (reached after consuming `depth`
+        /// nybbles of `key`), updates the copy's slot for `key`'s next
+        /// nybble, appends the copy, and returns its new index. Any child
+        /// block that also needs to change is copied first, recursively,
+        /// so the whole path from the insertion point up to (and
+        /// including) this block ends up appended, oldest-descendant
+        /// first.
+        fn insert_at(&mut self, block_idx: usize, depth: usize, key: K, value: V) -> usize {
+            if depth >= key.as_bytes().len() * 2 {
+                // `key` has no nybble left to route on at this depth, so it
+                // can only belong in `block_idx`'s terminal slot, not one of
+                // its 16 nybble slots.
+                return self.insert_terminal(block_idx, key, value);
+            }
+            let nybble = get_nybble(key.as_bytes(), depth);
+            let mut new_block = self.blocks[block_idx];
+            match new_block.get(nybble) {
+                NodeMapEntry::Empty => {
+                    let pos = self.push_entry(key, value, None);
+                    new_block.set(nybble, NodeMapEntry::Leaf(pos));
+                }
+                NodeMapEntry::Leaf(head) => {
+                    if self.entries[head].key == key {
+                        // Same key as an existing leaf: chain the new value
+                        // onto it instead of splitting the trie.
+                        let pos = self.push_entry(key, value, None);
+                        self.append_to_chain(head, pos);
+                    } else {
+                        let existing_key = self.entries[head].key.as_bytes().to_vec();
+                        match first_different_nybble(&existing_key, key.as_bytes()) {
+                            Some(diverge) => {
+                                let new_key_nybble = get_nybble(key.as_bytes(), diverge);
+                                let new_pos = self.push_entry(key, value, None);
+                                let mut child_idx = {
+                                    let mut leaf_block = NodeMapBlock::empty();
+                                    let existing_nybble = get_nybble(&existing_key, diverge);
+                                    leaf_block.set(existing_nybble, NodeMapEntry::Leaf(head));
+                                    leaf_block.set(new_key_nybble, NodeMapEntry::Leaf(new_pos));
+                                    self.blocks.push(leaf_block);
+                                    self.blocks.len() - 1
+                                };
+                                for d in (depth + 1..diverge).rev() {
+                                    let mut block = NodeMapBlock::empty();
+                                    block.set(
+                                        get_nybble(&existing_key, d),
+                                        NodeMapEntry::Child(child_idx),
+                                    );
+                                    self.blocks.push(block);
+                                    child_idx = self.blocks.len() - 1;
+                                }
+                                new_block.set(nybble, NodeMapEntry::Child(child_idx));
+                            }
+                            None => {
+                                // Neither key has a nybble to diverge on:
+                                // one is an exact byte-prefix of the other.
+                                // The shorter key becomes the terminal leaf
+                                // of a block reached at its own length,
+                                // while the longer key continues into that
+                                // block's nybble slot for the same depth.
+                                let new_pos = self.push_entry(key, value, None);
+                                let existing_len = existing_key.len() * 2;
+                                let new_len = self.entries[new_pos].key.as_bytes().len() * 2;
+                                let shorter_len = existing_len.min(new_len);
+                                let (shorter_pos, longer_key, longer_pos) =
+                                    if existing_len < new_len {
+                                        let new_key = self.entries[new_pos].key.as_bytes().to_vec();
+                                        (head, new_key, new_pos)
+                                    } else {
+                                        (new_pos, existing_key.clone(), head)
+                                    };
+                                let mut child_idx = {
+                                    let mut leaf_block = NodeMapBlock::empty();
+                                    leaf_block.set_terminal(NodeMapEntry::Leaf(shorter_pos));
+                                    leaf_block.set(
+                                        get_nybble(&longer_key, shorter_len),
+                                        NodeMapEntry::Leaf(longer_pos),
+                                    );
+                                    self.blocks.push(leaf_block);
+                                    self.blocks.len() - 1
+                                };
+                                for d in (depth + 1..shorter_len).rev() {
+                                    let mut block = NodeMapBlock::empty();
+                                    block.set(
+                                        get_nybble(&longer_key, d),
+                                        NodeMapEntry::Child(child_idx),
+                                    );
+                                    self.blocks.push(block);
+                                    child_idx = self.blocks.len() - 1;
+                                }
+                                new_block.set(nybble, NodeMapEntry::Child(child_idx));
+                            }
+                        }
+                    }
+                }
+                NodeMapEntry::Child(child_idx) => {
+                    let new_child_idx = self.insert_at(child_idx, depth + 1, key, value);
+                    new_block.set(nybble, NodeMapEntry::Child(new_child_idx));
+                }
+            }
+            self.blocks.push(new_block);
+            self.blocks.len() - 1
+        }
+
+        /// Inserts `key` → `value` into `block_idx`'s terminal slot, the
+        /// insert-path counterpart of [`Self::insert_at`]'s main match, used
+        /// when `key` has already run out of nybbles by the time it reaches
+        /// this block. Since at most one distinct key can ever be exhausted
+        /// at a given block's depth (two such keys would have shared every
+        /// nybble down to here, making them byte-identical), a non-empty
+        /// terminal slot can only hold a chain for this same `key`.
+        fn insert_terminal(&mut self, block_idx: usize, key: K, value: V) -> usize {
+            let mut new_block = self.blocks[block_idx];
+            match new_block.terminal() {
+                NodeMapEntry::Empty => {
+                    let pos = self.push_entry(key, value, None);
+                    new_block.set_terminal(NodeMapEntry::Leaf(pos));
+                }
+                NodeMapEntry::Leaf(head) => {
+                    let pos = self.push_entry(key, value, None);
+                    self.append_to_chain(head, pos);
+                }
+                NodeMapEntry::Child(_) => unreachable!("terminal slot is never a Child"),
+            }
+            self.blocks.push(new_block);
+            self.blocks.len() - 1
+        }
+
+        /// Number of valid bytes of block storage, for use in a
+        /// [`NodeMapDocket`].
+        pub fn valid_byte_len(&self) -> u64 {
+            (self.blocks.len() * BLOCK_BYTE_LEN) as u64
+        }
+    }
+
+    /// Tracks how many bytes of a nodemap file, as written by
+    /// [`NodeMapIndex::write_blocks`]/[`NodeMapIndex::write_new_blocks`],
+    /// are valid to read.
+    ///
+    /// [`NodeMapIndex::insert`] only ever appends blocks, so a reader that
+    /// already holds a docket can keep resolving prefixes against it with
+    /// [`resolve_prefix_from_reader`]: everything up to `valid_len` stays
+    /// byte-for-byte what it was, even after a writer calls
+    /// [`NodeMapIndex::write_new_blocks`] again to append more blocks past
+    /// it (and before it hands out the new docket that covers them).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct NodeMapDocket {
+        pub valid_len: u64,
+    }
+
+    impl NodeMapDocket {
+        pub fn new<K, V>(index: &NodeMapIndex<K, V>) -> Self
+        where
+            K: ObjectId + Ord,
+        {
+            NodeMapDocket {
+                valid_len: index.valid_byte_len(),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -261,4 +1065,374 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_node_map_index_resolve_prefix() {
+        use nodemap::NodeMapIndex;
+        fn sorted(resolution: PrefixResolution<Vec<i32>>) -> PrefixResolution<Vec<i32>> {
+            match resolution {
+                PrefixResolution::SingleMatch(mut xs) => {
+                    xs.sort();
+                    PrefixResolution::SingleMatch(xs)
+                }
+                _ => resolution,
+            }
+        }
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("0000"), 0),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0099"), 2),
+            (ChangeId::from_hex("0aaa"), 3),
+            (ChangeId::from_hex("0aab"), 4),
+        ]);
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("00").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("000").unwrap()),
+            PrefixResolution::SingleMatch(vec![0]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0001").unwrap()),
+            PrefixResolution::NoMatch,
+        );
+        assert_eq!(
+            sorted(node_map.resolve_prefix(&HexPrefix::new("009").unwrap())),
+            PrefixResolution::SingleMatch(vec![1, 2]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0aa").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0aab").unwrap()),
+            PrefixResolution::SingleMatch(vec![4]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("f").unwrap()),
+            PrefixResolution::NoMatch,
+        );
+    }
+
+    #[test]
+    fn test_node_map_index_resolve_prefix_exact_byte_prefix_key() {
+        use nodemap::NodeMapIndex;
+
+        // "01" is an exact byte-prefix of both "0123" and "0145": it has
+        // no nybble of its own at the depth where the other two diverge,
+        // so it must still count as a genuinely distinct key there rather
+        // than being folded onto one of them as if it were a duplicate.
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("01"), 0),
+            (ChangeId::from_hex("0123"), 1),
+            (ChangeId::from_hex("0145"), 2),
+        ]);
+        assert!(node_map.has_key(&ChangeId::from_hex("01")));
+        assert!(node_map.has_key(&ChangeId::from_hex("0123")));
+        assert!(node_map.has_key(&ChangeId::from_hex("0145")));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("01").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0123").unwrap()),
+            PrefixResolution::SingleMatch(vec![1]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0145").unwrap()),
+            PrefixResolution::SingleMatch(vec![2]),
+        );
+    }
+
+    #[test]
+    fn test_node_map_index_has_key() {
+        use nodemap::NodeMapIndex;
+        let node_map = NodeMapIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert!(!node_map.has_key(&ChangeId::from_hex("00")));
+
+        let node_map = NodeMapIndex::from_vec(vec![(ChangeId::from_hex("ab"), ())]);
+        assert!(!node_map.has_key(&ChangeId::from_hex("aa")));
+        assert!(node_map.has_key(&ChangeId::from_hex("ab")));
+        assert!(!node_map.has_key(&ChangeId::from_hex("ac")));
+    }
+
+    #[test]
+    fn test_node_map_index_resolve_prefix_from_reader() {
+        use std::io::Cursor;
+
+        use nodemap::{resolve_prefix_from_reader, NodeMapIndex};
+
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("0000"), 0),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0aaa"), 2),
+        ]);
+        let mut file = Cursor::new(Vec::new());
+        node_map.write_blocks(&mut file).unwrap();
+        let root = node_map.root().unwrap();
+
+        // A lookup driven entirely by seeking into the serialized bytes --
+        // never touching `node_map` itself -- agrees with the in-memory
+        // lookup on every outcome: unique, ambiguous, and no match.
+        assert!(matches!(
+            resolve_prefix_from_reader(&mut file, root, &HexPrefix::new("0099").unwrap()).unwrap(),
+            PrefixResolution::SingleMatch(_),
+        ));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0099").unwrap()),
+            PrefixResolution::SingleMatch(vec![1]),
+        );
+        assert_eq!(
+            resolve_prefix_from_reader(&mut file, root, &HexPrefix::new("00a").unwrap()).unwrap(),
+            PrefixResolution::NoMatch,
+        );
+        assert_eq!(
+            resolve_prefix_from_reader(&mut file, root, &HexPrefix::new("0").unwrap()).unwrap(),
+            PrefixResolution::AmbiguousMatch,
+        );
+    }
+
+    #[test]
+    fn test_node_map_index_write_new_blocks() {
+        use std::io::Cursor;
+
+        use nodemap::{resolve_prefix_from_reader, NodeMapDocket, NodeMapIndex};
+
+        let mut node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("0000"), 0),
+            (ChangeId::from_hex("0099"), 1),
+        ]);
+        let mut file = Cursor::new(Vec::new());
+        let docket = node_map
+            .write_new_blocks(NodeMapDocket { valid_len: 0 }, &mut file)
+            .unwrap();
+        let old_root = node_map.root().unwrap();
+        let resolution =
+            resolve_prefix_from_reader(&mut file, old_root, &HexPrefix::new("0099").unwrap())
+                .unwrap();
+        assert!(matches!(resolution, PrefixResolution::SingleMatch(_)));
+
+        // `insert` only appends blocks, so blocks written under an earlier
+        // docket are never rewritten: a reader still holding the old root
+        // and old docket keeps resolving the same way after more blocks
+        // are appended for a later insert.
+        node_map.insert(ChangeId::from_hex("0aaa"), 2);
+        let new_docket = node_map.write_new_blocks(docket, &mut file).unwrap();
+        assert!(new_docket.valid_len > docket.valid_len);
+        assert_eq!(
+            resolve_prefix_from_reader(&mut file, old_root, &HexPrefix::new("0099").unwrap())
+                .unwrap(),
+            resolution,
+        );
+        assert_eq!(
+            resolve_prefix_from_reader(
+                &mut file,
+                node_map.root().unwrap(),
+                &HexPrefix::new("0aaa").unwrap()
+            )
+            .unwrap(),
+            PrefixResolution::SingleMatch(2),
+        );
+    }
+
+    #[test]
+    fn test_node_map_index_insert() {
+        use nodemap::{NodeMapDocket, NodeMapIndex};
+
+        // Inserting into an empty index creates a root.
+        let mut node_map = NodeMapIndex::from_vec(vec![] as Vec<(ChangeId, i32)>);
+        node_map.insert(ChangeId::from_hex("0000"), 0);
+        assert!(node_map.has_key(&ChangeId::from_hex("0000")));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0000").unwrap()),
+            PrefixResolution::SingleMatch(vec![0]),
+        );
+
+        // Inserting a key that shares only its first nybble with the
+        // existing one should make both resolvable, and the earlier blocks should stay
+        // usable as a consistent (if stale) snapshot.
+        let docket_before = NodeMapDocket::new(&node_map);
+        node_map.insert(ChangeId::from_hex("0aaa"), 1);
+        assert!(docket_before.valid_len < NodeMapDocket::new(&node_map).valid_len);
+        assert!(node_map.has_key(&ChangeId::from_hex("0000")));
+        assert!(node_map.has_key(&ChangeId::from_hex("0aaa")));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0a").unwrap()),
+            PrefixResolution::SingleMatch(vec![1]),
+        );
+
+        // Inserting a key that shares a long common prefix with an
+        // existing one should still disambiguate both.
+        node_map.insert(ChangeId::from_hex("0aab"), 2);
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0aa").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0aaa").unwrap()),
+            PrefixResolution::SingleMatch(vec![1]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0aab").unwrap()),
+            PrefixResolution::SingleMatch(vec![2]),
+        );
+
+        // Inserting a duplicate key chains onto the existing leaf rather
+        // than splitting the trie.
+        node_map.insert(ChangeId::from_hex("0000"), 3);
+        let mut values = match node_map.resolve_prefix(&HexPrefix::new("0000").unwrap()) {
+            PrefixResolution::SingleMatch(values) => values,
+            other => panic!("expected SingleMatch, got {other:?}"),
+        };
+        values.sort();
+        assert_eq!(values, vec![0, 3]);
+    }
+
+    #[test]
+    fn test_node_map_index_insert_exact_byte_prefix_key() {
+        use nodemap::NodeMapIndex;
+
+        // Inserting a key that turns out to be an exact byte-prefix of an
+        // already-inserted leaf's key must not panic -- it has no nybble
+        // to diverge on, but is still a genuinely distinct key.
+        let mut node_map = NodeMapIndex::from_vec(vec![] as Vec<(ChangeId, i32)>);
+        node_map.insert(ChangeId::from_hex("0123"), 1);
+        node_map.insert(ChangeId::from_hex("01"), 0);
+        assert!(node_map.has_key(&ChangeId::from_hex("0123")));
+        assert!(node_map.has_key(&ChangeId::from_hex("01")));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("0123").unwrap()),
+            PrefixResolution::SingleMatch(vec![1]),
+        );
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("01").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+
+        // Same, but with the shorter key inserted first.
+        let mut node_map = NodeMapIndex::from_vec(vec![] as Vec<(ChangeId, i32)>);
+        node_map.insert(ChangeId::from_hex("01"), 0);
+        node_map.insert(ChangeId::from_hex("0123"), 1);
+        assert!(node_map.has_key(&ChangeId::from_hex("01")));
+        assert!(node_map.has_key(&ChangeId::from_hex("0123")));
+        assert_eq!(
+            node_map.resolve_prefix(&HexPrefix::new("01").unwrap()),
+            PrefixResolution::AmbiguousMatch,
+        );
+    }
+
+    #[test]
+    fn test_node_map_index_shortest_unique_prefix_len() {
+        use nodemap::NodeMapIndex;
+
+        // No crash if empty.
+        let node_map = NodeMapIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("00").unwrap()),
+            0
+        );
+
+        // With a single entry (or only duplicates of one key), nothing
+        // else competes, so even the empty prefix is already unambiguous.
+        let node_map = NodeMapIndex::from_vec(vec![(ChangeId::from_hex("ab"), ())]);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("").unwrap()),
+            0
+        );
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), 1),
+            (ChangeId::from_hex("ab"), 2),
+        ]);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("").unwrap()),
+            0
+        );
+
+        // Same, but queried with a non-empty prefix: still nothing to
+        // disambiguate from, so the query's own nybbles that happen to
+        // match the single stored key don't force a longer answer. A
+        // query that parts ways with the stored key does need enough
+        // nybbles to reach the point of divergence (plus one).
+        let node_map = NodeMapIndex::from_vec(vec![(ChangeId::from_hex("abcd"), ())]);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("abcd").unwrap()),
+            0
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("abce").unwrap()),
+            4
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("01").unwrap()),
+            1
+        );
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), 1),
+            (ChangeId::from_hex("ab"), 2),
+        ]);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("ab").unwrap()),
+            0
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("ac").unwrap()),
+            2
+        );
+
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), ()),
+            (ChangeId::from_hex("acd0"), ()),
+            (ChangeId::from_hex("acf0"), ()),
+            (ChangeId::from_hex("a0"), ()),
+            (ChangeId::from_hex("ba"), ()),
+        ]);
+
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("a0").unwrap()),
+            2
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("ba").unwrap()),
+            1
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("ab").unwrap()),
+            2
+        );
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("acd0").unwrap()),
+            3
+        );
+        // If it were there, the length would be 1.
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("c0").unwrap()),
+            1
+        );
+        // A prefix that several keys share but that is not itself a key.
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("ac").unwrap()),
+            3
+        );
+
+        // A prefix shorter than the point where its only two candidates
+        // actually diverge: the walk must keep following the lone branch
+        // past where the prefix itself ran out.
+        let node_map = NodeMapIndex::from_vec(vec![
+            (ChangeId::from_hex("aaaa"), ()),
+            (ChangeId::from_hex("aaab"), ()),
+        ]);
+        assert_eq!(
+            node_map.shortest_unique_prefix_len(&HexPrefix::new("aa").unwrap()),
+            4
+        );
+    }
 }
\ No newline at end of file